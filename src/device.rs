@@ -3,7 +3,18 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::fmt;
-use libc::{dev_t, major, makedev, minor};
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Read an entire file to a String, in the style of fs::read_to_string.
+fn read_to_string<P: AsRef<::std::path::Path>>(path: P) -> io::Result<String> {
+    let mut contents = String::new();
+    fs::File::open(path)?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
 
 /// A struct containing the device's major and minor numbers
 ///
@@ -23,18 +34,135 @@ impl fmt::Display for Device {
     }
 }
 
-impl From<dev_t> for Device {
-    fn from(val: dev_t) -> Device {
+impl FromStr for Device {
+    type Err = io::Error;
+
+    /// Parse a Device from sysfs's "<major>:<minor>" format, the same
+    /// format produced by Display.
+    fn from_str(s: &str) -> io::Result<Device> {
+        let mut parts = s.trim().splitn(2, ':');
+        let bad_format = || io::Error::new(io::ErrorKind::InvalidData, "expected \"major:minor\"");
+
+        let major = parts.next()
+            .ok_or_else(bad_format)?
+            .parse::<u32>()
+            .map_err(|_| bad_format())?;
+        let minor = parts.next()
+            .ok_or_else(bad_format)?
+            .parse::<u32>()
+            .map_err(|_| bad_format())?;
+
+        Ok(Device {
+            major: major,
+            minor: minor,
+        })
+    }
+}
+
+impl From<u64> for Device {
+    fn from(val: u64) -> Device {
+        Device::from_bits(val)
+    }
+}
+
+impl From<Device> for u64 {
+    fn from(dev: Device) -> u64 {
+        dev.bits()
+    }
+}
+
+/// Well-known pseudo-devices, as assigned in Linux's
+/// Documentation/admin-guide/devices.txt.
+impl Device {
+    /// /dev/null
+    pub const NULL: Device = Device { major: 1, minor: 3 };
+    /// /dev/zero
+    pub const ZERO: Device = Device { major: 1, minor: 5 };
+    /// /dev/full
+    pub const FULL: Device = Device { major: 1, minor: 7 };
+    /// /dev/urandom
+    pub const URANDOM: Device = Device { major: 1, minor: 9 };
+    /// /dev/tty
+    pub const TTY: Device = Device { major: 5, minor: 0 };
+    /// /dev/ptmx
+    pub const PTMX: Device = Device { major: 5, minor: 2 };
+
+    /// Make a Device from a 64-bit dev_t, per the Linux UAPI encoding
+    /// documented in include/linux/kdev_t.h, rather than relying on
+    /// glibc's major()/minor()/makedev(), which are implementation
+    /// defined and not usable in a const context.
+    pub const fn from_bits(dev: u64) -> Device {
         Device {
-            major: unsafe { major(val) },
-            minor: unsafe { minor(val) },
+            major: (((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff)) as u32,
+            minor: (((dev >> 12) & 0xffff_ff00) | (dev & 0xff)) as u32,
         }
     }
+
+    /// Convert to a 64-bit dev_t, per the Linux UAPI encoding.
+    pub const fn bits(&self) -> u64 {
+        let major = self.major as u64;
+        let minor = self.minor as u64;
+        ((major & 0xffff_f000) << 32) | ((major & 0xfff) << 8) | ((minor & 0xffff_ff00) << 12) |
+        (minor & 0xff)
+    }
 }
 
-impl From<Device> for dev_t {
-    fn from(dev: Device) -> dev_t {
-        unsafe { makedev(dev.major, dev.minor) }
+/// Read the Devices named by the "dev" files in each immediate
+/// subdirectory of dir, as found under a device's sysfs holders/ or
+/// slaves/ directory.
+fn devices_in_dir(dir: &::std::path::Path) -> io::Result<Vec<Device>> {
+    let mut result = Vec::new();
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // A device with no holders/slaves of its own has no such directory.
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(result),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let contents = read_to_string(entry?.path().join("dev"))?;
+        result.push(contents.parse()?);
+    }
+
+    Ok(result)
+}
+
+/// Resolve a Device number back to its /dev and sysfs nodes, following
+/// coreos-installer's sysfs-based discovery.
+impl Device {
+    /// The sysfs directory describing this device, e.g.
+    /// "/sys/dev/block/253:0".
+    pub fn sysfs_dir(&self) -> PathBuf {
+        PathBuf::from(format!("/sys/dev/block/{}:{}", self.major, self.minor))
+    }
+
+    /// Look up the canonical /dev path for this device by reading the
+    /// DEVNAME entry out of its sysfs uevent file.
+    pub fn devnode(&self) -> io::Result<PathBuf> {
+        let uevent = read_to_string(self.sysfs_dir().join("uevent"))?;
+
+        for line in uevent.lines() {
+            if line.starts_with("DEVNAME=") {
+                return Ok(PathBuf::from("/dev").join(&line["DEVNAME=".len()..]));
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::NotFound, "no DEVNAME entry in uevent"))
+    }
+
+    /// The devices stacked on top of this one, e.g. the device-mapper
+    /// targets built out of this device, by reading its sysfs holders/
+    /// directory.
+    pub fn holders(&self) -> io::Result<Vec<Device>> {
+        devices_in_dir(&self.sysfs_dir().join("holders"))
+    }
+
+    /// The devices this one is built out of, e.g. the physical devices
+    /// backing a device-mapper target, by reading its sysfs slaves/
+    /// directory.
+    pub fn slaves(&self) -> io::Result<Vec<Device>> {
+        devices_in_dir(&self.sysfs_dir().join("slaves"))
     }
 }
 
@@ -62,23 +190,110 @@ impl Device {
 #[cfg(test)]
 mod tests {
 
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
     use super::*;
 
     #[test]
     /// Verify conversion is correct both ways
     pub fn test_dev_t_conversion() {
-        let test_devt_1: dev_t = 0xabcdef1234567890;
+        let test_devt_1: u64 = 0xabcdef1234567890;
 
         let dev1 = Device::from(test_devt_1);
-        // Default glibc dev_t encoding is MMMM Mmmm mmmM MMmm. I guess if
-        // we're on a platform where non-default is used, we'll fail.
+        // Linux UAPI dev_t encoding is MMMM Mmmm mmmM MMmm.
         assert_eq!(dev1.major, 0xabcde678);
         assert_eq!(dev1.minor, 0xf1234590);
 
-        let test_devt_2: dev_t = dev_t::from(dev1);
+        let test_devt_2: u64 = u64::from(dev1);
         assert_eq!(test_devt_1, test_devt_2);
     }
 
+    #[test]
+    /// Well-known pseudo-devices round-trip through the dev_t encoding.
+    pub fn test_named_devices() {
+        assert_eq!(Device::from_bits(Device::NULL.bits()), Device::NULL);
+        assert_eq!(Device::from_bits(Device::URANDOM.bits()), Device::URANDOM);
+    }
+
+    #[test]
+    /// Parsing the sysfs "major:minor" format round-trips with Display.
+    pub fn test_device_from_str() {
+        let dev: Device = "253:0".parse().unwrap();
+        assert_eq!(dev,
+                   Device {
+                       major: 253,
+                       minor: 0,
+                   });
+        assert_eq!(dev.to_string(), "253:0");
+
+        assert!("253".parse::<Device>().is_err());
+        assert!("253:".parse::<Device>().is_err());
+        assert!("not_a_number:0".parse::<Device>().is_err());
+    }
+
+    #[test]
+    /// sysfs_dir() is pure formatting; no sysfs access required.
+    pub fn test_sysfs_dir() {
+        let dev = Device {
+            major: 253,
+            minor: 0,
+        };
+        assert_eq!(dev.sysfs_dir(), PathBuf::from("/sys/dev/block/253:0"));
+    }
+
+    #[test]
+    /// devices_in_dir reads the "dev" file out of each immediate
+    /// subdirectory, as sysfs's holders/slaves directories do.
+    pub fn test_devices_in_dir() {
+        let tmpdir = TempDir::new("devicemapper").unwrap();
+        let holders_dir = tmpdir.path().join("holders");
+        fs::create_dir(&holders_dir).unwrap();
+
+        let holder_entry = holders_dir.join("dm-1");
+        fs::create_dir(&holder_entry).unwrap();
+        fs::File::create(holder_entry.join("dev"))
+            .unwrap()
+            .write_all(b"253:1\n")
+            .unwrap();
+
+        let devices = devices_in_dir(&holders_dir).unwrap();
+        assert_eq!(devices,
+                   vec![Device {
+                            major: 253,
+                            minor: 1,
+                        }]);
+    }
+
+    #[test]
+    /// A device with no holders/slaves of its own has no such sysfs
+    /// directory at all; that's not an error, just an empty list.
+    pub fn test_devices_in_dir_missing_is_empty() {
+        let tmpdir = TempDir::new("devicemapper").unwrap();
+        let missing = tmpdir.path().join("holders");
+
+        assert_eq!(devices_in_dir(&missing).unwrap(), vec![]);
+    }
+
+    #[test]
+    /// A malformed "dev" file should propagate as an error, not panic or
+    /// get silently skipped.
+    pub fn test_devices_in_dir_malformed_dev_file() {
+        let tmpdir = TempDir::new("devicemapper").unwrap();
+        let dir = tmpdir.path().join("slaves");
+        fs::create_dir(&dir).unwrap();
+
+        let entry = dir.join("sda");
+        fs::create_dir(&entry).unwrap();
+        fs::File::create(entry.join("dev"))
+            .unwrap()
+            .write_all(b"garbage")
+            .unwrap();
+
+        assert!(devices_in_dir(&dir).is_err());
+    }
+
     #[test]
     /// Verify conversion is correct both ways
     pub fn test_kdev_t_conversion() {
@@ -0,0 +1,477 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Minimal GPT (GUID Partition Table) reader.
+//!
+//! Parses just enough of the protective MBR and GPT header/entries to let
+//! a device-mapper caller build linear targets that map onto specific
+//! GPT partitions by GUID or index, rather than hard-coding sector
+//! offsets. Mirrors the subset of the format that coreos-installer gets
+//! out of `gptman`.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+use super::consts::SECTOR_SIZE;
+use super::loopbacked::blkdev_size;
+
+/// The GPT header signature, "EFI PART".
+const GPT_SIGNATURE: u64 = 0x5452_4150_2049_4645;
+
+/// Size in bytes of a single GPT partition entry, per the UEFI spec.
+const GPT_ENTRY_SIZE: usize = 128;
+
+/// A 16-byte GUID, displayed in the usual mixed-endian textual form.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Guid([u8; 16]);
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(f,
+               "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-\
+                {:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+               b[3],
+               b[2],
+               b[1],
+               b[0],
+               b[5],
+               b[4],
+               b[7],
+               b[6],
+               b[8],
+               b[9],
+               b[10],
+               b[11],
+               b[12],
+               b[13],
+               b[14],
+               b[15])
+    }
+}
+
+/// A single entry in a GPT partition table.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct GptPartition {
+    /// Index of this entry in the partition table, starting at 1.
+    pub index: u32,
+    /// The GUID identifying the partition's type, e.g. Linux filesystem
+    /// data.
+    pub type_guid: Guid,
+    /// The GUID identifying this particular partition instance.
+    pub partition_guid: Guid,
+    /// First LBA of the partition, inclusive.
+    pub first_lba: u64,
+    /// Length of the partition, in sectors.
+    pub length: u64,
+    /// The partition's UTF-16 name, as recorded in the entry.
+    pub name: String,
+}
+
+/// Read a u64 out of a little-endian byte slice at the given offset.
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Read a u32 out of a little-endian byte slice at the given offset.
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+/// CRC-32 (IEEE 802.3), as used for the GPT header and partition entry
+/// array checksums. Implemented locally rather than pulling in a crate,
+/// since it's small and only ever needed here.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Check the protective MBR at LBA 0: a valid boot signature, and a
+/// partition table whose first entry has type 0xEE ("GPT protective"),
+/// as laid down by the UEFI spec so that MBR-only tools see the disk as
+/// fully allocated by one partition rather than empty. A GPT header at
+/// LBA 1 is only trustworthy if this is in place; otherwise it may just
+/// be leftover data from a previous, non-GPT partitioning scheme.
+fn has_protective_mbr(file: &mut File) -> io::Result<bool> {
+    let mut buf = [0u8; SECTOR_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut buf)?;
+
+    if buf[510] != 0x55 || buf[511] != 0xaa {
+        return Ok(false);
+    }
+
+    // The first of the MBR's four 16-byte partition entries starts at
+    // byte 446; its partition-type byte is 4 bytes into the entry.
+    const FIRST_PARTITION_ENTRY: usize = 446;
+    const PARTITION_TYPE_OFFSET: usize = 4;
+    const PARTITION_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+    Ok(buf[FIRST_PARTITION_ENTRY + PARTITION_TYPE_OFFSET] == PARTITION_TYPE_GPT_PROTECTIVE)
+}
+
+/// The parsed, validated fields of a GPT header that we care about.
+struct GptHeader {
+    partition_entry_lba: u64,
+    num_partition_entries: u32,
+    partition_entry_size: u32,
+    partition_entries_crc32: u32,
+}
+
+/// Read and validate the GPT header at the given LBA. Returns None if the
+/// signature or header CRC32 don't check out.
+fn read_header(file: &mut File, lba: u64) -> io::Result<Option<GptHeader>> {
+    let mut buf = [0u8; SECTOR_SIZE];
+    file.seek(SeekFrom::Start(lba * SECTOR_SIZE as u64))?;
+    file.read_exact(&mut buf)?;
+
+    if read_u64(&buf, 0) != GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    // Reject a header_size too small to cover the fields read below (up
+    // to the partition_entries_crc32 at offset 88) as well as too large
+    // for the sector we read it into. A torn or corrupt header can claim
+    // any header_size, and those fields are read directly out of `buf`
+    // rather than the CRC-covered `crc_buf`, so a too-small value would
+    // both panic on the `crc_buf[16..20]` zeroing below and let an
+    // attacker forge them outside the CRC's reach.
+    let header_size = read_u32(&buf, 12) as usize;
+    if header_size < 92 || header_size > buf.len() {
+        return Ok(None);
+    }
+
+    let header_crc32 = read_u32(&buf, 16);
+    let mut crc_buf = buf[..header_size].to_vec();
+    // The header's own CRC32 field is zeroed before computing the CRC.
+    for b in &mut crc_buf[16..20] {
+        *b = 0;
+    }
+    if crc32(&crc_buf) != header_crc32 {
+        return Ok(None);
+    }
+
+    Ok(Some(GptHeader {
+        partition_entry_lba: read_u64(&buf, 72),
+        num_partition_entries: read_u32(&buf, 80),
+        partition_entry_size: read_u32(&buf, 84),
+        partition_entries_crc32: read_u32(&buf, 88),
+    }))
+}
+
+/// Parse a single partition entry out of its raw bytes. Returns None for
+/// an unused entry (type GUID all zero).
+fn parse_entry(index: u32, entry: &[u8]) -> Option<GptPartition> {
+    let mut type_guid = [0u8; 16];
+    type_guid.copy_from_slice(&entry[0..16]);
+    if type_guid.iter().all(|&b| b == 0) {
+        return None;
+    }
+
+    let mut partition_guid = [0u8; 16];
+    partition_guid.copy_from_slice(&entry[16..32]);
+
+    let first_lba = read_u64(entry, 32);
+    let last_lba = read_u64(entry, 40);
+    if last_lba < first_lba {
+        return None;
+    }
+
+    let name_utf16: Vec<u16> = entry[56..128]
+        .chunks(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect();
+    let name = String::from_utf16_lossy(&name_utf16);
+
+    Some(GptPartition {
+        index: index,
+        type_guid: Guid(type_guid),
+        partition_guid: Guid(partition_guid),
+        first_lba: first_lba,
+        length: last_lba - first_lba + 1,
+        name: name,
+    })
+}
+
+/// An upper bound on the size of the partition entry array we're willing
+/// to read, well beyond the UEFI-specified minimum of 16384 bytes (128
+/// entries of 128 bytes). Guards against a corrupt or adversarial header
+/// driving an enormous allocation before the CRC32 check below can reject
+/// it.
+const MAX_PARTITION_TABLE_SIZE: usize = 1024 * 1024;
+
+/// Read the partition entries described by a validated GPT header.
+fn read_entries(file: &mut File, header: &GptHeader) -> io::Result<Vec<GptPartition>> {
+    if (header.partition_entry_size as usize) < GPT_ENTRY_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   "GPT partition entry size smaller than a partition entry"));
+    }
+
+    let table_len = header.num_partition_entries as usize *
+                    header.partition_entry_size as usize;
+    if table_len > MAX_PARTITION_TABLE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   "GPT partition entry array implausibly large"));
+    }
+
+    let mut table = vec![0u8; table_len];
+
+    file.seek(SeekFrom::Start(header.partition_entry_lba * SECTOR_SIZE as u64))?;
+    file.read_exact(&mut table)?;
+
+    if crc32(&table) != header.partition_entries_crc32 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   "GPT partition entry array CRC32 mismatch"));
+    }
+
+    let mut result = Vec::new();
+    for i in 0..header.num_partition_entries {
+        let start = i as usize * header.partition_entry_size as usize;
+        let entry = &table[start..start + GPT_ENTRY_SIZE];
+        if let Some(partition) = parse_entry(i + 1, entry) {
+            result.push(partition);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Read the GPT partition table off a block device. Validates the
+/// protective MBR at LBA 0, then tries the primary GPT header at LBA 1,
+/// falling back to the backup header at the last LBA of the device if
+/// the primary is missing or corrupt.
+pub fn read_gpt_partitions(file: &mut File) -> io::Result<Vec<GptPartition>> {
+    if !has_protective_mbr(file)? {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   "no protective MBR found at LBA 0"));
+    }
+
+    if let Some(header) = read_header(file, 1)? {
+        return read_entries(file, &header);
+    }
+
+    let total_sectors = *blkdev_size(file).sectors();
+    if total_sectors == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   "device too small to hold a GPT backup header"));
+    }
+    let last_lba = total_sectors - 1;
+    match read_header(file, last_lba)? {
+        Some(header) => read_entries(file, &header),
+        None => {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                                "no valid GPT header found at primary or backup location"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::*;
+    use super::super::loopbacked::test_with_spec;
+
+    /// Build a protective MBR: boot signature plus a single partition
+    /// entry of type 0xEE spanning the (fake, for our purposes) disk.
+    fn build_mbr() -> [u8; SECTOR_SIZE] {
+        let mut mbr = [0u8; SECTOR_SIZE];
+        mbr[446 + 4] = 0xee;
+        mbr[510] = 0x55;
+        mbr[511] = 0xaa;
+        mbr
+    }
+
+    fn build_entry(type_guid: [u8; 16],
+                    partition_guid: [u8; 16],
+                    first_lba: u64,
+                    last_lba: u64,
+                    name: &str)
+                    -> [u8; GPT_ENTRY_SIZE] {
+        let mut entry = [0u8; GPT_ENTRY_SIZE];
+        entry[0..16].copy_from_slice(&type_guid);
+        entry[16..32].copy_from_slice(&partition_guid);
+        entry[32..40].copy_from_slice(&first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&last_lba.to_le_bytes());
+
+        for (i, unit) in name.encode_utf16().enumerate() {
+            let bytes = unit.to_le_bytes();
+            entry[56 + i * 2] = bytes[0];
+            entry[56 + i * 2 + 1] = bytes[1];
+        }
+
+        entry
+    }
+
+    fn build_entries_table(entry: [u8; GPT_ENTRY_SIZE], num_entries: u32) -> Vec<u8> {
+        let mut table = vec![0u8; num_entries as usize * GPT_ENTRY_SIZE];
+        table[..GPT_ENTRY_SIZE].copy_from_slice(&entry);
+        table
+    }
+
+    /// Build a GPT header (primary or backup, depending on the LBAs
+    /// passed in), with its own CRC32 computed and embedded.
+    fn build_header(current_lba: u64,
+                     backup_lba: u64,
+                     first_usable_lba: u64,
+                     last_usable_lba: u64,
+                     partition_entry_lba: u64,
+                     num_partition_entries: u32,
+                     partition_entries_crc32: u32)
+                     -> [u8; SECTOR_SIZE] {
+        let mut header = [0u8; SECTOR_SIZE];
+        header[0..8].copy_from_slice(&GPT_SIGNATURE.to_le_bytes());
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&current_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&first_usable_lba.to_le_bytes());
+        header[48..56].copy_from_slice(&last_usable_lba.to_le_bytes());
+        header[72..80].copy_from_slice(&partition_entry_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&num_partition_entries.to_le_bytes());
+        header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&partition_entries_crc32.to_le_bytes());
+
+        let crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    /// Lay down a single-partition GPT across a freshly made loop device
+    /// and return the file handle it was written through, along with the
+    /// device's total sector count.
+    fn lay_down_gpt(path: &::std::path::Path) -> (File, u64) {
+        let mut f = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        let total_sectors = *blkdev_size(&f).sectors();
+
+        // The "Linux filesystem data" partition type GUID.
+        let type_guid = [0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69,
+                          0xd8, 0x47, 0x2d, 0xe4];
+        let partition_guid = [1u8; 16];
+        let entry = build_entry(type_guid, partition_guid, 2048, 2048 + 2047, "root");
+        let entries_table = build_entries_table(entry, 128);
+        let entries_crc = crc32(&entries_table);
+
+        let primary_entries_lba = 2;
+        let backup_entries_lba = total_sectors - 1 - 32;
+        let first_usable_lba = 34;
+        let last_usable_lba = total_sectors - 34;
+
+        let primary_header = build_header(1,
+                                           total_sectors - 1,
+                                           first_usable_lba,
+                                           last_usable_lba,
+                                           primary_entries_lba,
+                                           128,
+                                           entries_crc);
+        let backup_header = build_header(total_sectors - 1,
+                                          1,
+                                          first_usable_lba,
+                                          last_usable_lba,
+                                          backup_entries_lba,
+                                          128,
+                                          entries_crc);
+
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.write_all(&build_mbr()).unwrap();
+
+        f.seek(SeekFrom::Start(SECTOR_SIZE as u64)).unwrap();
+        f.write_all(&primary_header).unwrap();
+
+        f.seek(SeekFrom::Start(primary_entries_lba * SECTOR_SIZE as u64)).unwrap();
+        f.write_all(&entries_table).unwrap();
+
+        f.seek(SeekFrom::Start(backup_entries_lba * SECTOR_SIZE as u64)).unwrap();
+        f.write_all(&entries_table).unwrap();
+
+        f.seek(SeekFrom::Start((total_sectors - 1) * SECTOR_SIZE as u64)).unwrap();
+        f.write_all(&backup_header).unwrap();
+
+        f.flush().unwrap();
+
+        (f, total_sectors)
+    }
+
+    #[test]
+    /// Lay down a known single-partition GPT and check that it parses
+    /// back out via the primary header.
+    pub fn test_read_gpt_partitions() {
+        test_with_spec(1, |paths| {
+            let (mut f, _) = lay_down_gpt(paths[0]);
+
+            let partitions = read_gpt_partitions(&mut f).unwrap();
+            assert_eq!(partitions.len(), 1);
+            assert_eq!(partitions[0].first_lba, 2048);
+            assert_eq!(partitions[0].length, 2048);
+            assert_eq!(partitions[0].name, "root");
+        })
+    }
+
+    #[test]
+    /// A corrupt primary header should fall back to the backup header at
+    /// the end of the device.
+    pub fn test_read_gpt_partitions_backup_fallback() {
+        test_with_spec(1, |paths| {
+            let (mut f, _) = lay_down_gpt(paths[0]);
+
+            // Stomp on the primary header's signature.
+            f.seek(SeekFrom::Start(SECTOR_SIZE as u64)).unwrap();
+            f.write_all(&[0u8; 8]).unwrap();
+            f.flush().unwrap();
+
+            let partitions = read_gpt_partitions(&mut f).unwrap();
+            assert_eq!(partitions.len(), 1);
+            assert_eq!(partitions[0].name, "root");
+        })
+    }
+
+    #[test]
+    /// A primary header with an intact signature but an implausibly
+    /// small header_size (as a torn or corrupt write might leave behind)
+    /// must not panic, and should fall back to the backup header.
+    pub fn test_read_gpt_partitions_small_header_size_falls_back() {
+        test_with_spec(1, |paths| {
+            let (mut f, _) = lay_down_gpt(paths[0]);
+
+            f.seek(SeekFrom::Start(SECTOR_SIZE as u64 + 12)).unwrap();
+            f.write_all(&10u32.to_le_bytes()).unwrap();
+            f.flush().unwrap();
+
+            let partitions = read_gpt_partitions(&mut f).unwrap();
+            assert_eq!(partitions.len(), 1);
+            assert_eq!(partitions[0].name, "root");
+        })
+    }
+
+    #[test]
+    /// No protective MBR at LBA 0 means the device isn't trusted to be
+    /// GPT-partitioned, even if LBA 1 happens to look like a GPT header.
+    pub fn test_read_gpt_partitions_requires_protective_mbr() {
+        test_with_spec(1, |paths| {
+            let (mut f, _) = lay_down_gpt(paths[0]);
+
+            f.seek(SeekFrom::Start(0)).unwrap();
+            f.write_all(&[0u8; SECTOR_SIZE]).unwrap();
+            f.flush().unwrap();
+
+            assert!(read_gpt_partitions(&mut f).is_err());
+        })
+    }
+}
@@ -4,7 +4,7 @@
 
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
 use std::os::unix::prelude::AsRawFd;
 use std::path::{Path, PathBuf};
@@ -28,6 +28,74 @@ pub fn blkdev_size(file: &File) -> Bytes {
     Bytes(val)
 }
 
+/// The magic number found at the start of a Linux MD RAID superblock,
+/// as raw machine-endian bytes.
+const MD_MAGIC: u32 = 0xa92b4efc;
+
+/// The number of sectors reserved at the end of the device for a v0.90
+/// superblock, per LVM's dev-md.c.
+const MD_RESERVED_SECTORS: u64 = (64 * 1024) / 512;
+
+/// The version of an MD RAID superblock found on a device, if any.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MdSuperblock {
+    /// No MD superblock was found at any of the known locations.
+    None,
+    /// A v0.90 or v1.0 superblock, located at the end of the device. The
+    /// two share the same on-disk location, and telling them apart would
+    /// require decoding the superblock's minor-version field rather than
+    /// just matching its magic number, so they're reported together.
+    V0_90OrV1_0,
+    /// A v1.1 superblock, located at the start of the device.
+    V1_1,
+    /// A v1.2 superblock, located 4 KiB into the device.
+    V1_2,
+}
+
+/// Read a u32 at the given byte offset and check it against the MD magic,
+/// in both its raw (v0.90, machine-endian) and byte-swapped (v1.x,
+/// little-endian) forms.
+fn has_md_magic(file: &mut File, offset: u64) -> io::Result<bool> {
+    let mut buf = [0u8; 4];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+
+    let val = u32::from_ne_bytes(buf);
+    Ok(val == MD_MAGIC || val.swap_bytes() == MD_MAGIC)
+}
+
+/// Determine whether a block device already holds a Linux MD RAID
+/// superblock, following the probing order used by LVM's dev-md.c: the
+/// v0.90/v1.0 location at the end of the device, then the v1.1 location
+/// at the start, then the v1.2 location 4 KiB into the device.
+///
+/// For devices smaller than the reserved area at the end of the device,
+/// the end-of-device location is skipped rather than underflowing its
+/// offset computation; the start-of-device locations are still probed.
+pub fn probe_md_superblock(file: &mut File) -> io::Result<MdSuperblock> {
+    let total_sectors = blkdev_size(file).sectors();
+    let total_sectors = *total_sectors;
+
+    if total_sectors >= MD_RESERVED_SECTORS {
+        let end_sector = (total_sectors & !(MD_RESERVED_SECTORS - 1)) - MD_RESERVED_SECTORS;
+        let end_offset = end_sector * SECTOR_SIZE as u64;
+
+        if has_md_magic(file, end_offset)? {
+            return Ok(MdSuperblock::V0_90OrV1_0);
+        }
+    }
+
+    if has_md_magic(file, 0)? {
+        return Ok(MdSuperblock::V1_1);
+    }
+
+    if has_md_magic(file, 4 * 1024)? {
+        return Ok(MdSuperblock::V1_2);
+    }
+
+    Ok(MdSuperblock::None)
+}
+
 /// Get a device number from a device node.
 /// Return None if the device is not a block device; devicemapper is not
 /// interested in other sorts of devices.
@@ -62,6 +130,56 @@ fn wipe_sectors<P: AsRef<Path>>(path: P, offset: Sectors, length: Sectors) -> io
     write_sectors(path, offset, length, &[0u8; SECTOR_SIZE])
 }
 
+/// send IOCTL via BLKZEROOUT
+ioctl!(write blkzeroout with 0x12, 127; [u64; 2]);
+
+/// send IOCTL via BLKDISCARD
+ioctl!(write blkdiscard with 0x12, 119; [u64; 2]);
+
+/// Zero a byte range of a block device, offloading the work to the
+/// kernel via BLKZEROOUT when the driver supports it, as systemd and
+/// coreos-installer do. Falls back to a buffered write loop when the
+/// ioctl isn't supported by the underlying file, either because the
+/// driver itself declined (EOPNOTSUPP) or because the path isn't a block
+/// device at all (ENOTTY) -- e.g. a plain file standing in for a block
+/// device in tests.
+pub fn wipe_device<P: AsRef<Path>>(path: P, offset: Bytes, length: Bytes) -> io::Result<()> {
+    let f = OpenOptions::new().write(true).open(path.as_ref())?;
+    let range: [u64; 2] = [*offset, *length];
+
+    match unsafe { blkzeroout(f.as_raw_fd(), &range) } {
+        Ok(_) => Ok(()),
+        Err(nix::Error::Sys(nix::errno::Errno::EOPNOTSUPP)) |
+        Err(nix::Error::Sys(nix::errno::Errno::ENOTTY)) => {
+            let mut f = f;
+            f.seek(SeekFrom::Start(*offset))?;
+            let zeros = [0u8; SECTOR_SIZE];
+            let mut remaining = *length;
+            while remaining > 0 {
+                let n = ::std::cmp::min(remaining, SECTOR_SIZE as u64) as usize;
+                f.write_all(&zeros[..n])?;
+                remaining -= n as u64;
+            }
+            f.flush()
+        }
+        Err(e) => Err(io::Error::from_raw_os_error(e.errno() as i32)),
+    }
+}
+
+/// Discard a byte range of a block device via BLKDISCARD, letting thin
+/// or SSD-backed devices reclaim the space, following the approach
+/// systemd and coreos-installer use. Unlike `wipe_device`, there is no
+/// sensible buffered fallback: discard is advisory, so EOPNOTSUPP is
+/// simply reported to the caller.
+pub fn discard_device<P: AsRef<Path>>(path: P, offset: Bytes, length: Bytes) -> io::Result<()> {
+    let f = OpenOptions::new().write(true).open(path.as_ref())?;
+    let range: [u64; 2] = [*offset, *length];
+
+    unsafe { blkdiscard(f.as_raw_fd(), &range) }
+        .map(|_| ())
+        .map_err(|e| io::Error::from_raw_os_error(e.errno() as i32))
+}
+
 pub struct LoopTestDev {
     ld: LoopDevice,
 }
@@ -77,12 +195,15 @@ impl LoopTestDev {
         let ld = lc.next_free().unwrap();
         ld.attach(path, 0).unwrap();
 
-        // Wipe one MiB at the start of the device. Devicemapper data may be
-        // left on the device even after a teardown.
-        wipe_sectors(&ld.get_path().unwrap(),
-                     Sectors(0),
-                     Bytes(IEC::Mi).sectors())
-                .unwrap();
+        // Wipe the whole device. Devicemapper data, or a stale MD RAID
+        // superblock, may be left anywhere on the device even after a
+        // teardown.
+        let dev_path = ld.get_path().unwrap();
+        let size = {
+            let f = OpenOptions::new().read(true).open(&dev_path).unwrap();
+            blkdev_size(&f)
+        };
+        wipe_device(&dev_path, Bytes(0), size).unwrap();
 
         LoopTestDev { ld: ld }
     }
@@ -104,7 +225,7 @@ impl Drop for LoopTestDev {
 
 /// Setup count loop backed devices in dir.
 /// Make sure each loop device is backed by a 1 GiB file.
-/// Wipe the first 1 MiB of the file.
+/// Wipe the whole device.
 fn get_devices(count: u8, dir: &TempDir) -> Vec<LoopTestDev> {
     let lc = LoopControl::open().unwrap();
     let mut loop_devices = Vec::new();
@@ -144,3 +265,79 @@ pub fn test_with_spec<F>(count: u8, test: F) -> ()
 
     test(&device_paths);
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    /// Write an MD v1.1 superblock magic at the start of a loop device and
+    /// verify probe_md_superblock picks it up.
+    pub fn test_probe_md_superblock_v1_1() {
+        test_with_spec(1, |paths| {
+            let path = paths[0];
+
+            let mut magic_sector = [0u8; SECTOR_SIZE];
+            magic_sector[0..4].copy_from_slice(&MD_MAGIC.to_le_bytes());
+            write_sectors(path, Sectors(0), Sectors(1), &magic_sector).unwrap();
+
+            let mut f = OpenOptions::new().read(true).open(path).unwrap();
+            assert_eq!(probe_md_superblock(&mut f).unwrap(), MdSuperblock::V1_1);
+        })
+    }
+
+    #[test]
+    /// A freshly wiped loop device has no MD superblock.
+    pub fn test_probe_md_superblock_none() {
+        test_with_spec(1, |paths| {
+            let mut f = OpenOptions::new().read(true).open(paths[0]).unwrap();
+            assert_eq!(probe_md_superblock(&mut f).unwrap(), MdSuperblock::None);
+        })
+    }
+
+    #[test]
+    /// A plain file doesn't support the BLKZEROOUT ioctl, so wiping one
+    /// exercises the buffered-write fallback.
+    pub fn test_wipe_device_fallback_on_plain_file() {
+        let tmpdir = TempDir::new("devicemapper").unwrap();
+        let path = tmpdir.path().join("wipeme");
+
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+        f.write_all(&[0xffu8; SECTOR_SIZE]).unwrap();
+        f.flush().unwrap();
+
+        wipe_device(&path, Bytes(0), Bytes(SECTOR_SIZE as u64)).unwrap();
+
+        let mut buf = [0u8; SECTOR_SIZE];
+        let mut f = OpenOptions::new().read(true).open(&path).unwrap();
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &[0u8; SECTOR_SIZE][..]);
+    }
+
+    #[test]
+    /// A loop device is a real block device, so wiping it exercises the
+    /// BLKZEROOUT ioctl path.
+    pub fn test_wipe_device_via_ioctl_on_loop_device() {
+        test_with_spec(1, |paths| {
+            let path = paths[0];
+
+            write_sectors(path, Sectors(0), Sectors(1), &[0xffu8; SECTOR_SIZE]).unwrap();
+
+            let f = OpenOptions::new().read(true).open(path).unwrap();
+            let size = blkdev_size(&f);
+
+            wipe_device(path, Bytes(0), size).unwrap();
+
+            let mut buf = [0u8; SECTOR_SIZE];
+            let mut f = OpenOptions::new().read(true).open(path).unwrap();
+            f.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &[0u8; SECTOR_SIZE][..]);
+        })
+    }
+}